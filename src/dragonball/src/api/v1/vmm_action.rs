@@ -7,13 +7,18 @@
 // found in the THIRD-PARTY file.
 
 use std::fs::File;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{Receiver, Sender, TryRecvError};
 
 use log::{debug, error, info, warn};
+use vmm_sys_util::eventfd::EventFd;
 
 use crate::error::{Result, StartMicroVmError, StopMicrovmError};
 use crate::event_manager::EventManager;
-use crate::vm::{CpuTopology, KernelConfigInfo, VmConfigInfo};
+use crate::vm::{
+    CpuTopology, DeviceTransportType, KernelConfigInfo, NumaConfig, NumaNode, VmConfigInfo,
+    VmConfigUpdateInfo,
+};
 use crate::vmm::Vmm;
 
 use self::VmConfigError::*;
@@ -25,18 +30,117 @@ pub use crate::device_manager::blk_dev_mgr::{
 };
 #[cfg(feature = "virtio-fs")]
 pub use crate::device_manager::fs_dev_mgr::{
-    FsDeviceConfigInfo, FsDeviceConfigUpdateInfo, FsDeviceError, FsDeviceMgr, FsMountConfigInfo,
+    FsBackendType, FsDeviceConfigInfo, FsDeviceConfigUpdateInfo, FsDeviceError, FsDeviceMgr,
+    FsMountConfigInfo,
 };
 #[cfg(feature = "virtio-net")]
 pub use crate::device_manager::virtio_net_dev_mgr::{
-    VirtioNetDeviceConfigInfo, VirtioNetDeviceConfigUpdateInfo, VirtioNetDeviceError,
-    VirtioNetDeviceMgr,
+    NetBackendType, VirtioNetDeviceConfigInfo, VirtioNetDeviceConfigUpdateInfo,
+    VirtioNetDeviceError, VirtioNetDeviceMgr,
 };
 #[cfg(feature = "virtio-vsock")]
-pub use crate::device_manager::vsock_dev_mgr::{VsockDeviceConfigInfo, VsockDeviceError};
+pub use crate::device_manager::vsock_dev_mgr::{
+    VsockBackendType, VsockDeviceConfigInfo, VsockDeviceConfigUpdateInfo, VsockDeviceError,
+};
+#[cfg(feature = "vfio")]
+pub use crate::device_manager::vfio_dev_mgr::{VfioDeviceConfigInfo, VfioDeviceError, VfioDeviceMgr};
+#[cfg(feature = "snapshot")]
+pub use crate::vm::snapshot::{RestoreConfig, SnapshotConfig, SnapshotError};
 
 use super::*;
 
+/// The output mode of the guest console/serial device, following the cloud-hypervisor style of
+/// letting operators pick a backend instead of always wiring up a Unix socket.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConsoleOutputMode {
+    /// The console is disabled; guest output is discarded.
+    Off,
+    /// The console inherits the VMM process' stdio.
+    Tty,
+    /// The console is redirected to a regular file at the given path.
+    File(PathBuf),
+    /// The console is exposed as a Unix domain socket at the given path. This matches the
+    /// default serial socket that `set_vm_configuration` sets up under `/run/dragonball/`.
+    Socket(PathBuf),
+    /// The console is exposed through a freshly allocated pseudo-terminal; the slave path is
+    /// returned to the caller via `VmmData::ConsoleDevicePath`.
+    Pty,
+}
+
+/// Configuration for the `SetConsoleConfig` action.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConsoleConfigInfo {
+    /// The selected console output mode.
+    pub mode: ConsoleOutputMode,
+}
+
+/// Errors associated with configuring the guest console/serial device.
+#[derive(Debug, thiserror::Error)]
+pub enum ConsoleConfigError {
+    /// The update operation is not allowed after the microVM has booted.
+    #[error("the console configuration cannot be changed after boot")]
+    UpdateNotAllowedPostBoot,
+
+    /// Failed to create or open the backend described by the selected `ConsoleOutputMode`.
+    #[error("failed to create console backend: {0}")]
+    CreateConsoleBackend(#[source] std::io::Error),
+}
+
+#[cfg(feature = "virtio-net")]
+/// Configuration for a virtio-net device whose datapath is delegated to an external vhost-user
+/// backend process over a Unix-domain socket (e.g. a standalone `vhost-user-net` daemon),
+/// mirroring how crosvm wires up its `VhostUserNet` device.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VhostUserNetDeviceConfigInfo {
+    /// Unique identifier for this network interface.
+    pub iface_id: String,
+    /// Path of the vhost-user control socket exposed by the backend process.
+    pub vhost_user_path: String,
+}
+
+/// Errors associated with the GDB remote-serial-protocol debug stub.
+#[cfg(feature = "gdb")]
+#[derive(Debug, thiserror::Error)]
+pub enum GdbServerError {
+    /// `StartGdbServer` was called while the microVM wasn't running.
+    #[error("the virtual machine is not running")]
+    MicroVMNotRunning,
+
+    /// `StartGdbServer` was called while a GDB server was already attached.
+    #[error("a GDB server is already running for this microVM")]
+    AlreadyRunning,
+
+    /// `StopGdbServer` was called while no GDB server was attached.
+    #[error("no GDB server is running for this microVM")]
+    NotRunning,
+
+    /// Failed to bind the GDB remote-serial-protocol Unix socket.
+    #[error("failed to start the GDB server: {0}")]
+    SocketListenFailed(#[source] std::io::Error),
+
+    /// Failed to pause the vCPU threads before attaching the GDB server, or to resume them after
+    /// a failed attach.
+    #[error("failed to pause or resume the virtual machine: {0}")]
+    PauseFailed(String),
+}
+
+/// Errors associated with creating an ELF core dump of a running or paused guest.
+#[cfg(feature = "coredump")]
+#[derive(Debug, thiserror::Error)]
+pub enum CoredumpError {
+    /// `CreateCoredump` was called while the microVM wasn't running or paused.
+    #[error("the virtual machine must be running or paused to create a coredump")]
+    InvalidVmState,
+
+    /// Failed to pause the vCPU threads before dumping guest memory, or to resume them afterwards.
+    #[error("failed to pause or resume the virtual machine: {0}")]
+    PauseFailed(String),
+
+    /// Failed to write the ELF core dump to the destination file.
+    #[error("failed to write coredump: {0}")]
+    WriteFailed(#[source] std::io::Error),
+}
+
 /// Wrapper for all errors associated with VMM actions.
 #[derive(Debug, thiserror::Error)]
 pub enum VmmActionError {
@@ -48,6 +152,16 @@ pub enum VmmActionError {
     #[error("Upcall not ready, can't hotplug device.")]
     UpcallNotReady,
 
+    /// Failed to clone the `activate_evt` shared with device managers, e.g. because the host is
+    /// out of file descriptors.
+    #[error("failed to clone the device activation eventfd: {0}")]
+    ActivateEvtCloneFailed(#[source] std::io::Error),
+
+    /// The selected device backend cannot be used: its vhost kernel device node is missing on
+    /// the host, or a vhost-user backend was selected without a socket path.
+    #[error("requested device backend is unavailable: {0}")]
+    BackendUnavailable(String),
+
     /// The action `ConfigureBootSource` failed either because of bad user input or an internal
     /// error.
     #[error("failed to configure boot source for VM: {0}")]
@@ -67,8 +181,9 @@ pub enum VmmActionError {
     MachineConfig(#[source] VmConfigError),
 
     #[cfg(feature = "virtio-vsock")]
-    /// The action `InsertVsockDevice` failed either because of bad user input or an internal error.
-    #[error("failed to add virtio-vsock device: {0}")]
+    /// The actions `InsertVsockDevice`/`RemoveVsockDevice` failed either because of bad user
+    /// input or an internal error.
+    #[error("virtio-vsock device error: {0}")]
     Vsock(#[source] VsockDeviceError),
 
     #[cfg(feature = "virtio-blk")]
@@ -85,6 +200,34 @@ pub enum VmmActionError {
     /// The action `InsertFsDevice` failed either because of bad user input or an internal error.
     #[error("virtio-fs device error: {0}")]
     FsDevice(#[source] FsDeviceError),
+
+    #[cfg(feature = "vfio")]
+    /// The actions `InsertVfioDevice`/`RemoveVfioDevice` failed either because of bad user input
+    /// or an internal error.
+    #[error("vfio device error: {0}")]
+    Vfio(#[source] VfioDeviceError),
+
+    #[cfg(feature = "snapshot")]
+    /// One of the actions `PauseMicroVm`, `ResumeMicroVm`, `SnapshotMicroVm` or `RestoreMicroVm`
+    /// failed either because of bad user input or an internal error.
+    #[error("failed to snapshot/restore the VM: {0}")]
+    Snapshot(#[source] SnapshotError),
+
+    /// The action `SetConsoleConfig` failed either because of bad user input or an internal
+    /// error.
+    #[error("failed to configure the guest console: {0}")]
+    ConsoleConfig(#[source] ConsoleConfigError),
+
+    #[cfg(feature = "gdb")]
+    /// One of the actions `StartGdbServer`/`StopGdbServer` failed either because of bad user
+    /// input or an internal error.
+    #[error("gdb server error: {0}")]
+    GdbServer(#[source] GdbServerError),
+
+    #[cfg(feature = "coredump")]
+    /// The action `CreateCoredump` failed either because of bad user input or an internal error.
+    #[error("failed to create coredump: {0}")]
+    Coredump(#[source] CoredumpError),
 }
 
 /// This enum represents the public interface of the VMM. Each action contains various
@@ -113,9 +256,24 @@ pub enum VmmAction {
     #[cfg(feature = "virtio-vsock")]
     /// Add a new vsock device or update one that already exists using the
     /// `VsockDeviceConfig` as input. This action can only be called before the microVM has
-    /// booted. The response is sent using the `OutcomeSender`.
+    /// booted. The response is sent using the `OutcomeSender`. The config's `backend` field
+    /// selects whether the datapath is handled in-process or offloaded to the host kernel via
+    /// `/dev/vhost-vsock` (see [`VsockBackendType`]); guest CID validation and the
+    /// post-boot guard apply the same way regardless of backend.
     InsertVsockDevice(VsockDeviceConfigInfo),
 
+    #[cfg(feature = "virtio-vsock")]
+    /// Remove the vsock device identified by the given stable device id, hot-unplugging it if
+    /// the microVM has already booted.
+    RemoveVsockDevice(String),
+
+    #[cfg(feature = "virtio-vsock")]
+    /// Update a vsock device, after microVM start. Currently, the only updatable properties
+    /// are the RX and TX rate limiters. The fs equivalent of this action is `UpdateFsDevice`,
+    /// which already covers post-boot fs rate-limiter updates, so no separate
+    /// `UpdateFsRateLimiter` variant is needed.
+    UpdateVsockRateLimiter(VsockDeviceConfigUpdateInfo),
+
     #[cfg(feature = "virtio-blk")]
     /// Add a new block device or update one that already exists using the `BlockDeviceConfig` as
     /// input. This action can only be called before the microVM has booted.
@@ -133,7 +291,9 @@ pub enum VmmAction {
     #[cfg(feature = "virtio-net")]
     /// Add a new network interface config or update one that already exists using the
     /// `NetworkInterfaceConfig` as input. This action can only be called before the microVM has
-    /// booted. The response is sent using the `OutcomeSender`.
+    /// booted. The response is sent using the `OutcomeSender`. The config's `backend` field
+    /// selects between the in-VMM datapath and the host kernel's `/dev/vhost-net` (see
+    /// [`NetBackendType`]); the `UpcallMissVsock` hotplug precondition is enforced either way.
     InsertNetworkDevice(VirtioNetDeviceConfigInfo),
 
     #[cfg(feature = "virtio-net")]
@@ -144,7 +304,8 @@ pub enum VmmAction {
     #[cfg(feature = "virtio-fs")]
     /// Add a new shared fs device or update one that already exists using the
     /// `FsDeviceConfig` as input. This action can only be called before the microVM has
-    /// booted.
+    /// booted. The config's `backend` field selects whether the filesystem daemon runs inside
+    /// this process or out-of-process behind a vhost-user socket (see [`FsBackendType`]).
     InsertFsDevice(FsDeviceConfigInfo),
 
     #[cfg(feature = "virtio-fs")]
@@ -156,6 +317,83 @@ pub enum VmmAction {
     #[cfg(feature = "virtio-fs")]
     /// Update fs rate limiter, after microVM start.
     UpdateFsDevice(FsDeviceConfigUpdateInfo),
+
+    #[cfg(feature = "vfio")]
+    /// Add a new VFIO PCI passthrough device using the `VfioDeviceConfigInfo` as input. This
+    /// action can be called before the microVM has booted, or after boot when the `hotplug`
+    /// feature is enabled.
+    InsertVfioDevice(VfioDeviceConfigInfo),
+
+    #[cfg(feature = "vfio")]
+    /// Remove a VFIO PCI passthrough device according to the given guest slot identifier.
+    RemoveVfioDevice(String),
+
+    #[cfg(feature = "snapshot")]
+    /// Pause all vcpu threads at an instruction boundary and quiesce virtio queues. This action
+    /// can only be called after the microVM has booted.
+    ///
+    /// This is the VMM's one checkpoint/pause surface: requests for a separate
+    /// `Pause`/`Resume`/`CreateSnapshot`/`Restore` set of variants are served by
+    /// `PauseMicroVm`/`ResumeMicroVm`/`SnapshotMicroVm`/`RestoreMicroVm` instead of adding a
+    /// second, parallel API that would race the same underlying VM state.
+    PauseMicroVm,
+
+    #[cfg(feature = "snapshot")]
+    /// Resume a previously paused microVM, restarting its vcpu threads and virtio queues.
+    ResumeMicroVm,
+
+    #[cfg(feature = "snapshot")]
+    /// Serialize the CPU manager's registers/MSRs, the memory manager's dirty-region metadata
+    /// plus a backing guest-RAM file, and each device's state into a JSON `snapshot-config`
+    /// (describing `VmConfigInfo`/device layout) and a binary `snapshot-state` blob, as described
+    /// by `SnapshotConfig`. Pauses the VM first unless it is already paused, then resumes it
+    /// afterwards. This plays the role a `CreateSnapshot { destination }` variant would.
+    SnapshotMicroVm(SnapshotConfig),
+
+    #[cfg(feature = "snapshot")]
+    /// Reconstruct a microVM from the `snapshot-config`/`snapshot-state` artifacts produced by
+    /// `SnapshotMicroVm`, as described by `RestoreConfig`. This action can only be called before
+    /// the microVM has booted. This plays the role a `Restore { source }` variant would.
+    RestoreMicroVm(RestoreConfig),
+
+    #[cfg(feature = "hotplug")]
+    /// Bring additional vcpus online (up to `max_vcpu_count`) and/or grow guest memory (up to
+    /// the configured maximum) on an already running microVM, using `VmConfigUpdateInfo` as
+    /// input.
+    UpdateVmConfiguration(VmConfigUpdateInfo),
+
+    /// Configure the guest console/serial output mode using `ConsoleConfigInfo` as input. This
+    /// action can only be called before the microVM has booted.
+    SetConsoleConfig(ConsoleConfigInfo),
+
+    #[cfg(feature = "gdb")]
+    /// Start a GDB remote-serial-protocol server listening on `socket_path`, for `gdb`/`lldb`-
+    /// driven kernel debugging of a live microVM. This action can only be called after the
+    /// microVM has booted.
+    StartGdbServer {
+        /// Path of the Unix socket the GDB server listens on.
+        socket_path: String,
+    },
+
+    #[cfg(feature = "gdb")]
+    /// Stop a previously started GDB server, detaching from the microVM and resuming it if it
+    /// was paused on attach.
+    StopGdbServer,
+
+    #[cfg(feature = "coredump")]
+    /// Produce an ELF64 core dump of guest RAM plus per-vCPU register notes at `destination`,
+    /// for post-mortem kernel analysis. Pauses the VM first unless it is already paused, and
+    /// leaves it in whichever state it was found.
+    CreateCoredump {
+        /// Path of the file the core dump is written to.
+        destination: String,
+    },
+
+    #[cfg(feature = "virtio-net")]
+    /// Add a new network interface whose datapath is delegated to an external vhost-user
+    /// backend process, using `VhostUserNetDeviceConfigInfo` as input. This action can only be
+    /// called before the microVM has booted.
+    InsertVhostUserNetDevice(VhostUserNetDeviceConfigInfo),
 }
 
 /// The enum represents the response sent by the VMM in case of success. The response is either
@@ -166,6 +404,9 @@ pub enum VmmData {
     Empty,
     /// The microVM configuration represented by `VmConfigInfo`.
     MachineConfiguration(Box<VmConfigInfo>),
+    /// The path of the device allocated for the guest console, e.g. the slave side of a
+    /// pseudo-terminal allocated for `ConsoleOutputMode::Pty`.
+    ConsoleDevicePath(PathBuf),
 }
 
 /// Request data type used to communicate between the API and the VMM.
@@ -224,7 +465,17 @@ impl VmmService {
                 self.set_vm_configuration(vmm, machine_config)
             }
             #[cfg(feature = "virtio-vsock")]
-            VmmAction::InsertVsockDevice(vsock_cfg) => self.add_vsock_device(vmm, vsock_cfg),
+            VmmAction::InsertVsockDevice(vsock_cfg) => {
+                self.add_vsock_device(vmm, event_mgr, vsock_cfg)
+            }
+            #[cfg(feature = "virtio-vsock")]
+            VmmAction::RemoveVsockDevice(vsock_id) => {
+                self.remove_vsock_device(vmm, event_mgr, &vsock_id)
+            }
+            #[cfg(feature = "virtio-vsock")]
+            VmmAction::UpdateVsockRateLimiter(vsock_update) => {
+                self.update_vsock_rate_limiters(vmm, vsock_update)
+            }
             #[cfg(feature = "virtio-blk")]
             VmmAction::InsertBlockDevice(block_device_config) => {
                 self.add_block_device(vmm, event_mgr, block_device_config)
@@ -246,7 +497,7 @@ impl VmmService {
                 self.update_net_rate_limiters(vmm, netif_update)
             }
             #[cfg(feature = "virtio-fs")]
-            VmmAction::InsertFsDevice(fs_cfg) => self.add_fs_device(vmm, fs_cfg),
+            VmmAction::InsertFsDevice(fs_cfg) => self.add_fs_device(vmm, event_mgr, fs_cfg),
 
             #[cfg(feature = "virtio-fs")]
             VmmAction::ManipulateFsBackendFs(fs_mount_cfg) => {
@@ -256,6 +507,45 @@ impl VmmService {
             VmmAction::UpdateFsDevice(fs_update_cfg) => {
                 self.update_fs_rate_limiters(vmm, fs_update_cfg)
             }
+            #[cfg(feature = "vfio")]
+            VmmAction::InsertVfioDevice(vfio_cfg) => {
+                self.add_vfio_device(vmm, event_mgr, vfio_cfg)
+            }
+            #[cfg(feature = "vfio")]
+            VmmAction::RemoveVfioDevice(vfio_id) => {
+                self.remove_vfio_device(vmm, event_mgr, &vfio_id)
+            }
+            #[cfg(feature = "snapshot")]
+            VmmAction::PauseMicroVm => self.pause_microvm(vmm),
+            #[cfg(feature = "snapshot")]
+            VmmAction::ResumeMicroVm => self.resume_microvm(vmm),
+            #[cfg(feature = "snapshot")]
+            VmmAction::SnapshotMicroVm(snapshot_cfg) => self.snapshot_microvm(vmm, snapshot_cfg),
+            #[cfg(feature = "snapshot")]
+            VmmAction::RestoreMicroVm(restore_cfg) => {
+                self.restore_microvm(vmm, event_mgr, restore_cfg)
+            }
+            #[cfg(feature = "hotplug")]
+            VmmAction::UpdateVmConfiguration(update_cfg) => {
+                self.update_vm_configuration(vmm, event_mgr, update_cfg)
+            }
+            VmmAction::SetConsoleConfig(console_cfg) => {
+                self.set_console_config(vmm, console_cfg)
+            }
+            #[cfg(feature = "gdb")]
+            VmmAction::StartGdbServer { socket_path } => {
+                self.start_gdb_server(vmm, socket_path)
+            }
+            #[cfg(feature = "gdb")]
+            VmmAction::StopGdbServer => self.stop_gdb_server(vmm),
+            #[cfg(feature = "coredump")]
+            VmmAction::CreateCoredump { destination } => {
+                self.create_coredump(vmm, destination)
+            }
+            #[cfg(feature = "virtio-net")]
+            VmmAction::InsertVhostUserNetDevice(vhost_user_net_cfg) => {
+                self.add_vhost_user_net_device(vmm, event_mgr, vhost_user_net_cfg)
+            }
         };
 
         debug!("send vmm response: {:?}", response);
@@ -271,6 +561,28 @@ impl VmmService {
         Ok(())
     }
 
+    // Clone the `activate_evt` registered with the `EventManager`. Device managers write to
+    // their own copy once the guest driver has written DRIVER_OK, and the VMM event loop wakes
+    // up on the shared fd to walk the pending-activation list.
+    fn clone_activate_evt(event_mgr: &EventManager) -> std::result::Result<EventFd, VmmActionError> {
+        event_mgr
+            .activate_evt()
+            .try_clone()
+            .map_err(VmmActionError::ActivateEvtCloneFailed)
+    }
+
+    /// Walk the list of devices that finished feature negotiation (DRIVER_OK) but haven't had
+    /// their worker threads spawned yet, and activate them. Invoked by the event handler when
+    /// the shared `activate_evt` registered with the `EventManager` fires, so queues aren't
+    /// activated before the guest driver is actually ready for them.
+    pub fn try_activate_pending_devices(&mut self, vmm: &mut Vmm) -> Result<()> {
+        if let Some(vm) = vmm.get_vm_mut() {
+            vm.device_manager_mut().try_activate_pending_devices();
+        }
+
+        Ok(())
+    }
+
     fn configure_boot_source(
         &self,
         vmm: &mut Vmm,
@@ -391,6 +703,15 @@ impl VmmService {
         config.cpu_pm = machine_config.cpu_pm;
         config.mem_type = machine_config.mem_type;
 
+        // Select which virtio transport newly inserted devices are placed on. Defaults to MMIO
+        // for backward compatibility; PCI is only available when built with the `pci` feature
+        // and is a no-op on non-x86_64 targets for now.
+        if cfg!(feature = "pci") {
+            config.device_transport = machine_config.device_transport;
+        } else if machine_config.device_transport != DeviceTransportType::Mmio {
+            return Err(MachineConfig(InvalidDeviceTransport));
+        }
+
         let mem_size_mib_value = machine_config.mem_size_mib;
         // Support 1TB memory at most, 2MB aligned for huge page.
         if mem_size_mib_value == 0 || mem_size_mib_value > 0x10_0000 || mem_size_mib_value % 2 != 0
@@ -406,6 +727,28 @@ impl VmmService {
         }
         config.vpmu_feature = machine_config.vpmu_feature;
 
+        // Cap the guest-physical address space at the requested width, clamping down to the
+        // host's reported physical-address bits rather than erroring when it's too wide. This
+        // lets operators pin a stable address-space layout for migration between heterogeneous
+        // hosts, matching cloud-hypervisor's memory manager.
+        if let Some(requested_phys_bits) = machine_config.max_phys_bits {
+            let max_phys_bits = requested_phys_bits.min(host_phys_bits());
+            let phys_addr_space_mib = (1u64 << max_phys_bits) / 0x10_0000;
+            if phys_addr_space_mib < config.mem_size_mib + MIN_DEVICE_WINDOW_MIB {
+                return Err(MachineConfig(InvalidPhysBits));
+            }
+            config.max_phys_bits = Some(max_phys_bits);
+        }
+
+        if !machine_config.numa_nodes.nodes.is_empty() {
+            handle_numa_topology(
+                &machine_config.numa_nodes,
+                config.vcpu_count,
+                config.mem_size_mib,
+            )?;
+            config.numa_nodes = machine_config.numa_nodes.clone();
+        }
+
         let vm_id = vm.shared_info().read().unwrap().id.clone();
         let serial_path = match machine_config.serial_path {
             Some(value) => value,
@@ -427,7 +770,12 @@ impl VmmService {
     }
 
     #[cfg(feature = "virtio-vsock")]
-    fn add_vsock_device(&self, vmm: &mut Vmm, config: VsockDeviceConfigInfo) -> VmmRequestResult {
+    fn add_vsock_device(
+        &self,
+        vmm: &mut Vmm,
+        event_mgr: &mut EventManager,
+        config: VsockDeviceConfigInfo,
+    ) -> VmmRequestResult {
         let vm = vmm.get_vm_mut().ok_or(VmmActionError::InvalidVMID)?;
         if vm.is_vm_initialized() {
             return Err(VmmActionError::Vsock(
@@ -445,11 +793,22 @@ impl VmmService {
             )));
         }
 
+        if let VsockBackendType::VhostKernel = config.backend {
+            Path::new("/dev/vhost-vsock").metadata().map_err(|e| {
+                VmmActionError::BackendUnavailable(format!(
+                    "vhost-vsock kernel backend requested but /dev/vhost-vsock is unavailable: {e}"
+                ))
+            })?;
+        }
+
         info!("add_vsock_device: {:?}", config);
-        let ctx = vm.create_device_op_context(None).map_err(|e| {
-            info!("create device op context error: {:?}", e);
-            VmmActionError::Vsock(VsockDeviceError::UpdateNotAllowedPostBoot)
-        })?;
+        let activate_evt = Self::clone_activate_evt(event_mgr)?;
+        let ctx = vm
+            .create_device_op_context(None, Some(activate_evt))
+            .map_err(|e| {
+                info!("create device op context error: {:?}", e);
+                VmmActionError::Vsock(VsockDeviceError::UpdateNotAllowedPostBoot)
+            })?;
 
         vm.device_manager_mut()
             .vsock_manager
@@ -458,6 +817,41 @@ impl VmmService {
             .map_err(VmmActionError::Vsock)
     }
 
+    #[cfg(feature = "virtio-vsock")]
+    fn remove_vsock_device(
+        &mut self,
+        vmm: &mut Vmm,
+        event_mgr: &mut EventManager,
+        vsock_id: &str,
+    ) -> VmmRequestResult {
+        let vm = vmm.get_vm_mut().ok_or(VmmActionError::InvalidVMID)?;
+        let activate_evt = Self::clone_activate_evt(event_mgr)?;
+        let ctx = vm
+            .create_device_op_context(Some(event_mgr.epoll_manager()), Some(activate_evt))
+            .map_err(|_| VmmActionError::Vsock(VsockDeviceError::UpdateNotAllowedPostBoot))?;
+
+        vm.device_manager_mut()
+            .vsock_manager
+            .remove_device(ctx, vsock_id)
+            .map(|_| VmmData::Empty)
+            .map_err(VmmActionError::Vsock)
+    }
+
+    #[cfg(feature = "virtio-vsock")]
+    fn update_vsock_rate_limiters(
+        &mut self,
+        vmm: &mut Vmm,
+        config: VsockDeviceConfigUpdateInfo,
+    ) -> VmmRequestResult {
+        let vm = vmm.get_vm_mut().ok_or(VmmActionError::InvalidVMID)?;
+
+        vm.device_manager_mut()
+            .vsock_manager
+            .update_device_ratelimiters(config)
+            .map(|_| VmmData::Empty)
+            .map_err(VmmActionError::Vsock)
+    }
+
     #[cfg(feature = "virtio-blk")]
     // Only call this function as part of the API.
     // If the drive_id does not exist, a new Block Device Config is added to the list.
@@ -468,8 +862,9 @@ impl VmmService {
         config: BlockDeviceConfigInfo,
     ) -> VmmRequestResult {
         let vm = vmm.get_vm_mut().ok_or(VmmActionError::InvalidVMID)?;
+        let activate_evt = Self::clone_activate_evt(event_mgr)?;
         let ctx = vm
-            .create_device_op_context(Some(event_mgr.epoll_manager()))
+            .create_device_op_context(Some(event_mgr.epoll_manager()), Some(activate_evt))
             .map_err(|e| {
                 if let StartMicroVmError::UpcallNotReady = e {
                     return VmmActionError::UpcallNotReady;
@@ -505,8 +900,9 @@ impl VmmService {
         drive_id: &str,
     ) -> VmmRequestResult {
         let vm = vmm.get_vm_mut().ok_or(VmmActionError::InvalidVMID)?;
+        let activate_evt = Self::clone_activate_evt(event_mgr)?;
         let ctx = vm
-            .create_device_op_context(Some(event_mgr.epoll_manager()))
+            .create_device_op_context(Some(event_mgr.epoll_manager()), Some(activate_evt))
             .map_err(|_| VmmActionError::Block(BlockDeviceError::UpdateNotAllowedPostBoot))?;
 
         BlockDeviceMgr::remove_device(vm.device_manager_mut(), ctx, drive_id)
@@ -521,9 +917,18 @@ impl VmmService {
         event_mgr: &mut EventManager,
         config: VirtioNetDeviceConfigInfo,
     ) -> VmmRequestResult {
+        if let NetBackendType::VhostKernel = config.backend {
+            Path::new("/dev/vhost-net").metadata().map_err(|e| {
+                VmmActionError::BackendUnavailable(format!(
+                    "vhost-net kernel backend requested but /dev/vhost-net is unavailable: {e}"
+                ))
+            })?;
+        }
+
         let vm = vmm.get_vm_mut().ok_or(VmmActionError::InvalidVMID)?;
+        let activate_evt = Self::clone_activate_evt(event_mgr)?;
         let ctx = vm
-            .create_device_op_context(Some(event_mgr.epoll_manager()))
+            .create_device_op_context(Some(event_mgr.epoll_manager()), Some(activate_evt))
             .map_err(|e| {
                 if let StartMicroVmError::MicroVMAlreadyRunning = e {
                     VmmActionError::VirtioNet(VirtioNetDeviceError::UpdateNotAllowedPostBoot)
@@ -552,8 +957,50 @@ impl VmmService {
             .map_err(VmmActionError::VirtioNet)
     }
 
+    #[cfg(feature = "virtio-net")]
+    // Negotiate the vhost-user protocol with the backend over its control socket, sharing the
+    // guest memory table and virtqueue eventfds/kickfds, instead of driving the datapath
+    // in-VMM.
+    fn add_vhost_user_net_device(
+        &mut self,
+        vmm: &mut Vmm,
+        event_mgr: &mut EventManager,
+        config: VhostUserNetDeviceConfigInfo,
+    ) -> VmmRequestResult {
+        let vm = vmm.get_vm_mut().ok_or(VmmActionError::InvalidVMID)?;
+        let activate_evt = Self::clone_activate_evt(event_mgr)?;
+        let ctx = vm
+            .create_device_op_context(Some(event_mgr.epoll_manager()), Some(activate_evt))
+            .map_err(|e| {
+                if let StartMicroVmError::MicroVMAlreadyRunning = e {
+                    VmmActionError::VirtioNet(VirtioNetDeviceError::UpdateNotAllowedPostBoot)
+                } else if let StartMicroVmError::UpcallNotReady = e {
+                    VmmActionError::UpcallNotReady
+                } else {
+                    VmmActionError::StartMicroVm(e)
+                }
+            })?;
+
+        VirtioNetDeviceMgr::insert_vhost_user_device(vm.device_manager_mut(), ctx, config)
+            .map(|_| VmmData::Empty)
+            .map_err(VmmActionError::VirtioNet)
+    }
+
     #[cfg(feature = "virtio-fs")]
-    fn add_fs_device(&mut self, vmm: &mut Vmm, config: FsDeviceConfigInfo) -> VmmRequestResult {
+    fn add_fs_device(
+        &mut self,
+        vmm: &mut Vmm,
+        event_mgr: &mut EventManager,
+        config: FsDeviceConfigInfo,
+    ) -> VmmRequestResult {
+        if let FsBackendType::VhostUserFs { socket } = &config.backend {
+            if socket.is_empty() {
+                return Err(VmmActionError::BackendUnavailable(
+                    "vhost-user-fs backend requested but no socket path was given".to_string(),
+                ));
+            }
+        }
+
         let vm = vmm.get_vm_mut().ok_or(VmmActionError::InvalidVMID)?;
         let hotplug = vm.is_vm_initialized();
         if !cfg!(feature = "hotplug") && hotplug {
@@ -562,10 +1009,13 @@ impl VmmService {
             ));
         }
 
-        let ctx = vm.create_device_op_context(None).map_err(|e| {
-            info!("create device op context error: {:?}", e);
-            VmmActionError::FsDevice(FsDeviceError::UpdateNotAllowedPostBoot)
-        })?;
+        let activate_evt = Self::clone_activate_evt(event_mgr)?;
+        let ctx = vm
+            .create_device_op_context(None, Some(activate_evt))
+            .map_err(|e| {
+                info!("create device op context error: {:?}", e);
+                VmmActionError::FsDevice(FsDeviceError::UpdateNotAllowedPostBoot)
+            })?;
         FsDeviceMgr::insert_device(vm.device_manager_mut(), ctx, config)
             .map(|_| VmmData::Empty)
             .map_err(VmmActionError::FsDevice)
@@ -604,97 +1054,421 @@ impl VmmService {
             .map(|_| VmmData::Empty)
             .map_err(VmmActionError::FsDevice)
     }
-}
 
-fn handle_cpu_topology(
-    cpu_topology: &CpuTopology,
-    vcpu_count: u8,
-) -> std::result::Result<&CpuTopology, VmmActionError> {
-    // Check if dies_per_socket, cores_per_die, threads_per_core and socket number is valid
-    if cpu_topology.threads_per_core < 1 || cpu_topology.threads_per_core > 2 {
-        return Err(MachineConfig(InvalidThreadsPerCore(
-            cpu_topology.threads_per_core,
-        )));
-    }
-    let vcpu_count_from_topo = cpu_topology
-        .sockets
-        .checked_mul(cpu_topology.dies_per_socket)
-        .ok_or(MachineConfig(VcpuCountExceedsMaximum))?
-        .checked_mul(cpu_topology.cores_per_die)
-        .ok_or(MachineConfig(VcpuCountExceedsMaximum))?
-        .checked_mul(cpu_topology.threads_per_core)
-        .ok_or(MachineConfig(VcpuCountExceedsMaximum))?;
-    if vcpu_count_from_topo > MAX_SUPPORTED_VCPUS {
-        return Err(MachineConfig(VcpuCountExceedsMaximum));
-    }
-    if vcpu_count_from_topo < vcpu_count {
-        return Err(MachineConfig(InvalidCpuTopology(vcpu_count_from_topo)));
-    }
+    #[cfg(feature = "vfio")]
+    // Only call this function as part of the API.
+    // Opens the host VFIO group, maps the device's BAR regions into guest memory and wires up
+    // MSI/MSI-X routing. Supports pre-boot attach, and post-boot hotplug when the `hotplug`
+    // feature is enabled.
+    fn add_vfio_device(
+        &mut self,
+        vmm: &mut Vmm,
+        event_mgr: &mut EventManager,
+        config: VfioDeviceConfigInfo,
+    ) -> VmmRequestResult {
+        let vm = vmm.get_vm_mut().ok_or(VmmActionError::InvalidVMID)?;
+        let activate_evt = Self::clone_activate_evt(event_mgr)?;
+        let ctx = vm
+            .create_device_op_context(Some(event_mgr.epoll_manager()), Some(activate_evt))
+            .map_err(|e| {
+                if let StartMicroVmError::UpcallNotReady = e {
+                    return VmmActionError::UpcallNotReady;
+                }
+                VmmActionError::Vfio(VfioDeviceError::UpdateNotAllowedPostBoot)
+            })?;
 
-    Ok(cpu_topology)
-}
+        VfioDeviceMgr::insert_device(vm.device_manager_mut(), ctx, config)
+            .map(|_| VmmData::Empty)
+            .map_err(VmmActionError::Vfio)
+    }
 
-#[cfg(test)]
-mod tests {
-    use std::sync::mpsc::channel;
-    use std::sync::{Arc, Mutex};
+    #[cfg(feature = "vfio")]
+    // Remove the VFIO device, releasing its BAR mappings and MSI/MSI-X routing.
+    fn remove_vfio_device(
+        &mut self,
+        vmm: &mut Vmm,
+        event_mgr: &mut EventManager,
+        vfio_id: &str,
+    ) -> VmmRequestResult {
+        let vm = vmm.get_vm_mut().ok_or(VmmActionError::InvalidVMID)?;
+        let activate_evt = Self::clone_activate_evt(event_mgr)?;
+        let ctx = vm
+            .create_device_op_context(Some(event_mgr.epoll_manager()), Some(activate_evt))
+            .map_err(|_| VmmActionError::Vfio(VfioDeviceError::UpdateNotAllowedPostBoot))?;
 
-    use dbs_utils::epoll_manager::EpollManager;
-    use test_utils::skip_if_not_root;
-    use vmm_sys_util::tempfile::TempFile;
+        VfioDeviceMgr::remove_device(vm.device_manager_mut(), ctx, vfio_id)
+            .map(|_| VmmData::Empty)
+            .map_err(VmmActionError::Vfio)
+    }
 
-    use super::*;
-    use crate::vmm::tests::create_vmm_instance;
+    #[cfg(feature = "snapshot")]
+    // Stop all vcpu threads at an instruction boundary and quiesce virtio queues.
+    fn pause_microvm(&mut self, vmm: &mut Vmm) -> VmmRequestResult {
+        let vm = vmm.get_vm_mut().ok_or(VmmActionError::InvalidVMID)?;
+        if !vm.is_vm_initialized() {
+            return Err(VmmActionError::Snapshot(SnapshotError::MicroVMNotRunning));
+        }
 
-    struct TestData<'a> {
-        req: Option<VmmAction>,
-        vm_state: InstanceState,
-        f: &'a dyn Fn(VmmRequestResult),
+        vm.pause_vm()
+            .map(|_| VmmData::Empty)
+            .map_err(VmmActionError::Snapshot)
     }
 
-    impl<'a> TestData<'a> {
-        fn new(req: VmmAction, vm_state: InstanceState, f: &'a dyn Fn(VmmRequestResult)) -> Self {
-            Self {
-                req: Some(req),
-                vm_state,
-                f,
-            }
+    #[cfg(feature = "snapshot")]
+    // Restart vcpu threads and virtio queues quiesced by a previous pause.
+    fn resume_microvm(&mut self, vmm: &mut Vmm) -> VmmRequestResult {
+        let vm = vmm.get_vm_mut().ok_or(VmmActionError::InvalidVMID)?;
+        if !vm.is_vm_initialized() {
+            return Err(VmmActionError::Snapshot(SnapshotError::MicroVMNotRunning));
         }
 
-        fn check_request(&mut self) {
-            let (to_vmm, from_api) = channel();
-            let (to_api, from_vmm) = channel();
-
-            let vmm = Arc::new(Mutex::new(create_vmm_instance()));
-            let mut vservice = VmmService::new(from_api, to_api);
+        vm.resume_vm()
+            .map(|_| VmmData::Empty)
+            .map_err(VmmActionError::Snapshot)
+    }
 
-            let epoll_mgr = EpollManager::default();
-            let mut event_mgr = EventManager::new(&vmm, epoll_mgr).unwrap();
-            let mut v = vmm.lock().unwrap();
+    #[cfg(feature = "snapshot")]
+    // Serialize vcpu register state, guest RAM and device manager state into the destination
+    // described by `config`. Resumes the VM afterwards unless it was already paused.
+    fn snapshot_microvm(&mut self, vmm: &mut Vmm, config: SnapshotConfig) -> VmmRequestResult {
+        let vm = vmm.get_vm_mut().ok_or(VmmActionError::InvalidVMID)?;
+        if !vm.is_vm_initialized() {
+            return Err(VmmActionError::Snapshot(SnapshotError::MicroVMNotRunning));
+        }
 
-            let vm = v.get_vm_mut().unwrap();
-            vm.set_instance_state(self.vm_state);
+        // Remember whether the VM was already paused so we leave it in the same state we found
+        // it, rather than always resuming after the snapshot is taken.
+        let was_paused = vm.is_paused();
+        if !was_paused {
+            vm.pause_vm().map_err(VmmActionError::Snapshot)?;
+        }
 
-            to_vmm.send(Box::new(self.req.take().unwrap())).unwrap();
-            assert!(vservice.run_vmm_action(&mut v, &mut event_mgr).is_ok());
+        let result = vm
+            .snapshot(&config)
+            .map(|_| VmmData::Empty)
+            .map_err(VmmActionError::Snapshot);
 
-            let response = from_vmm.try_recv();
-            assert!(response.is_ok());
-            (self.f)(*response.unwrap());
+        if !was_paused {
+            vm.resume_vm().map_err(VmmActionError::Snapshot)?;
         }
+
+        result
     }
 
-    #[test]
-    fn test_vmm_action_receive_unknown() {
-        skip_if_not_root!();
+    #[cfg(feature = "snapshot")]
+    // Reconstruct `VmConfigInfo`, memory and devices from a previously created snapshot, then
+    // reload vcpu state before resuming.
+    fn restore_microvm(
+        &mut self,
+        vmm: &mut Vmm,
+        event_mgr: &mut EventManager,
+        config: RestoreConfig,
+    ) -> VmmRequestResult {
+        let vm = vmm.get_vm_mut().ok_or(VmmActionError::InvalidVMID)?;
+        if vm.is_vm_initialized() {
+            return Err(VmmActionError::Snapshot(SnapshotError::StateInvalid));
+        }
 
-        let (_to_vmm, from_api) = channel();
-        let (to_api, _from_vmm) = channel();
-        let vmm = Arc::new(Mutex::new(create_vmm_instance()));
-        let mut vservice = VmmService::new(from_api, to_api);
-        let epoll_mgr = EpollManager::default();
-        let mut event_mgr = EventManager::new(&vmm, epoll_mgr).unwrap();
-        let mut v = vmm.lock().unwrap();
+        vm.restore(event_mgr, &config)
+            .map(|_| VmmData::Empty)
+            .map_err(VmmActionError::Snapshot)
+    }
+
+    #[cfg(feature = "hotplug")]
+    /// Bring additional vcpus online and/or grow guest memory on a running microVM.
+    fn update_vm_configuration(
+        &mut self,
+        vmm: &mut Vmm,
+        event_mgr: &mut EventManager,
+        update_config: VmConfigUpdateInfo,
+    ) -> VmmRequestResult {
+        let vm = vmm.get_vm_mut().ok_or(VmmActionError::InvalidVMID)?;
+        if !vm.is_vm_initialized() {
+            return Err(MachineConfig(MicroVMNotRunning));
+        }
+
+        let config = vm.vm_config().clone();
+        if let Some(vcpu_count) = update_config.vcpu_count {
+            if vcpu_count > config.max_vcpu_count {
+                return Err(MachineConfig(InvalidVcpuCount(vcpu_count)));
+            }
+            if vcpu_count < vm.online_vcpu_count() {
+                return Err(MachineConfig(InvalidVcpuCount(vcpu_count)));
+            }
+        }
+        if let Some(mem_size_mib) = update_config.mem_size_mib {
+            if mem_size_mib < config.mem_size_mib || mem_size_mib > config.max_mem_size_mib {
+                return Err(MachineConfig(InvalidMemorySize(mem_size_mib)));
+            }
+        }
+
+        // Hotplugging vcpus/memory relies on the upcall channel to notify the guest agent,
+        // exactly like the existing hotplug precondition for `add_block_device`.
+        vm.create_device_op_context(Some(event_mgr.epoll_manager()), None)
+            .map_err(|e| {
+                if let StartMicroVmError::UpcallNotReady = e {
+                    VmmActionError::UpcallNotReady
+                } else {
+                    VmmActionError::StartMicroVm(e)
+                }
+            })?;
+
+        vm.update_vm_configuration(update_config)
+            .map(|_| VmmData::Empty)
+            .map_err(MachineConfig)
+    }
+
+    /// Create the backend for the selected `ConsoleOutputMode` before boot. For `Pty`, the
+    /// allocated slave path is returned to the caller so it can connect a terminal to it.
+    fn set_console_config(&mut self, vmm: &mut Vmm, config: ConsoleConfigInfo) -> VmmRequestResult {
+        let vm = vmm.get_vm_mut().ok_or(VmmActionError::InvalidVMID)?;
+        if vm.is_vm_initialized() {
+            return Err(VmmActionError::ConsoleConfig(
+                ConsoleConfigError::UpdateNotAllowedPostBoot,
+            ));
+        }
+
+        vm.set_console_config(config)
+            .map(|device_path| match device_path {
+                Some(path) => VmmData::ConsoleDevicePath(path),
+                None => VmmData::Empty,
+            })
+            .map_err(VmmActionError::ConsoleConfig)
+    }
+
+    #[cfg(feature = "gdb")]
+    // Pause the guest on attach, reusing the same vCPU pause plumbing as snapshotting, and start
+    // a GDB remote-serial-protocol server over the given Unix socket. Mirrors `create_coredump`'s
+    // was_paused bookkeeping so a failed attach doesn't strand the VM paused.
+    fn start_gdb_server(&mut self, vmm: &mut Vmm, socket_path: String) -> VmmRequestResult {
+        let vm = vmm.get_vm_mut().ok_or(VmmActionError::InvalidVMID)?;
+        if !vm.is_vm_initialized() {
+            return Err(VmmActionError::GdbServer(GdbServerError::MicroVMNotRunning));
+        }
+        if vm.is_gdb_server_attached() {
+            return Err(VmmActionError::GdbServer(GdbServerError::AlreadyRunning));
+        }
+
+        let was_paused = vm.is_paused();
+        if !was_paused {
+            vm.pause_vm()
+                .map_err(|e| VmmActionError::GdbServer(GdbServerError::PauseFailed(e.to_string())))?;
+        }
+
+        let result = vm
+            .start_gdb_server(socket_path)
+            .map(|_| VmmData::Empty)
+            .map_err(VmmActionError::GdbServer);
+
+        if result.is_err() && !was_paused {
+            vm.resume_vm()
+                .map_err(|e| VmmActionError::GdbServer(GdbServerError::PauseFailed(e.to_string())))?;
+        }
+
+        result
+    }
+
+    #[cfg(feature = "gdb")]
+    // Detach the GDB server and resume the guest if it was paused on attach.
+    fn stop_gdb_server(&mut self, vmm: &mut Vmm) -> VmmRequestResult {
+        let vm = vmm.get_vm_mut().ok_or(VmmActionError::InvalidVMID)?;
+        if !vm.is_gdb_server_attached() {
+            return Err(VmmActionError::GdbServer(GdbServerError::NotRunning));
+        }
+
+        vm.stop_gdb_server()
+            .map(|_| VmmData::Empty)
+            .map_err(VmmActionError::GdbServer)
+    }
+
+    #[cfg(feature = "coredump")]
+    // Following cloud-hypervisor's coredump flow: remember whether the VM was already paused,
+    // pause it if not, write the ELF core dump, then resume unless it was already paused.
+    fn create_coredump(&mut self, vmm: &mut Vmm, destination: String) -> VmmRequestResult {
+        let vm = vmm.get_vm_mut().ok_or(VmmActionError::InvalidVMID)?;
+        if !vm.is_vm_initialized() {
+            return Err(VmmActionError::Coredump(CoredumpError::InvalidVmState));
+        }
+
+        let was_paused = vm.is_paused();
+        if !was_paused {
+            vm.pause_vm().map_err(|e| {
+                VmmActionError::Coredump(CoredumpError::PauseFailed(e.to_string()))
+            })?;
+        }
+
+        let result = vm
+            .create_coredump(&destination)
+            .map(|_| VmmData::Empty)
+            .map_err(VmmActionError::Coredump);
+
+        if !was_paused {
+            vm.resume_vm()
+                .map_err(|e| VmmActionError::Coredump(CoredumpError::PauseFailed(e.to_string())))?;
+        }
+
+        result
+    }
+}
+
+fn handle_cpu_topology(
+    cpu_topology: &CpuTopology,
+    vcpu_count: u8,
+) -> std::result::Result<&CpuTopology, VmmActionError> {
+    // Check if dies_per_socket, cores_per_die, threads_per_core and socket number is valid
+    if cpu_topology.threads_per_core < 1 || cpu_topology.threads_per_core > 2 {
+        return Err(MachineConfig(InvalidThreadsPerCore(
+            cpu_topology.threads_per_core,
+        )));
+    }
+    let vcpu_count_from_topo = cpu_topology
+        .sockets
+        .checked_mul(cpu_topology.dies_per_socket)
+        .ok_or(MachineConfig(VcpuCountExceedsMaximum))?
+        .checked_mul(cpu_topology.cores_per_die)
+        .ok_or(MachineConfig(VcpuCountExceedsMaximum))?
+        .checked_mul(cpu_topology.threads_per_core)
+        .ok_or(MachineConfig(VcpuCountExceedsMaximum))?;
+    if vcpu_count_from_topo > MAX_SUPPORTED_VCPUS {
+        return Err(MachineConfig(VcpuCountExceedsMaximum));
+    }
+    if vcpu_count_from_topo < vcpu_count {
+        return Err(MachineConfig(InvalidCpuTopology(vcpu_count_from_topo)));
+    }
+
+    Ok(cpu_topology)
+}
+
+// Minimum address space, in MiB, reserved below the top of the guest-physical range for the
+// MMIO/device hole, regardless of how much RAM is configured.
+const MIN_DEVICE_WINDOW_MIB: u64 = 256;
+
+#[cfg(target_arch = "x86_64")]
+fn host_phys_bits() -> u8 {
+    // CPUID leaf 0x80000008, EAX[7:0] reports the maximum physical address width.
+    let cpuid = unsafe { std::arch::x86_64::__cpuid(0x8000_0008) };
+    (cpuid.eax & 0xff) as u8
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn host_phys_bits() -> u8 {
+    40
+}
+
+const NUMA_LOCAL_DISTANCE: u8 = 10;
+
+fn handle_numa_topology(
+    numa_config: &NumaConfig,
+    vcpu_count: u8,
+    mem_size_mib: u64,
+) -> std::result::Result<&NumaConfig, VmmActionError> {
+    let node_count = numa_config.nodes.len();
+
+    // Every vCPU id must appear in exactly one node, and the union of all nodes' vCPU ids must
+    // be exactly the set of vCPUs the VM was configured with.
+    let mut seen_vcpus = vec![false; vcpu_count as usize];
+    let mut total_mem_mib: u64 = 0;
+    for node in &numa_config.nodes {
+        total_mem_mib = total_mem_mib
+            .checked_add(node.memory_mib)
+            .ok_or(MachineConfig(NumaMemoryMismatch))?;
+        for &vcpu_id in &node.vcpu_ids {
+            match seen_vcpus.get_mut(vcpu_id as usize) {
+                Some(seen) if !*seen => *seen = true,
+                _ => return Err(MachineConfig(InvalidNumaNode(node.node_id))),
+            }
+        }
+    }
+    if seen_vcpus.iter().any(|&seen| !seen) {
+        return Err(MachineConfig(InvalidNumaNode(u32::MAX)));
+    }
+    if total_mem_mib != mem_size_mib {
+        return Err(MachineConfig(NumaMemoryMismatch));
+    }
+
+    // The distance matrix must be square, symmetric, and carry the standard "local" distance on
+    // its diagonal.
+    if numa_config.distances.len() != node_count {
+        return Err(MachineConfig(InvalidNumaDistance));
+    }
+    for (i, row) in numa_config.distances.iter().enumerate() {
+        if row.len() != node_count {
+            return Err(MachineConfig(InvalidNumaDistance));
+        }
+        for (j, &distance) in row.iter().enumerate() {
+            if i == j && distance != NUMA_LOCAL_DISTANCE {
+                return Err(MachineConfig(InvalidNumaDistance));
+            }
+            if numa_config.distances[j].get(i) != Some(&distance) {
+                return Err(MachineConfig(InvalidNumaDistance));
+            }
+        }
+    }
+
+    Ok(numa_config)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use std::sync::{Arc, Mutex};
+
+    use dbs_utils::epoll_manager::EpollManager;
+    use test_utils::skip_if_not_root;
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::*;
+    use crate::vmm::tests::create_vmm_instance;
+
+    struct TestData<'a> {
+        req: Option<VmmAction>,
+        vm_state: InstanceState,
+        f: &'a dyn Fn(VmmRequestResult),
+    }
+
+    impl<'a> TestData<'a> {
+        fn new(req: VmmAction, vm_state: InstanceState, f: &'a dyn Fn(VmmRequestResult)) -> Self {
+            Self {
+                req: Some(req),
+                vm_state,
+                f,
+            }
+        }
+
+        fn check_request(&mut self) {
+            let (to_vmm, from_api) = channel();
+            let (to_api, from_vmm) = channel();
+
+            let vmm = Arc::new(Mutex::new(create_vmm_instance()));
+            let mut vservice = VmmService::new(from_api, to_api);
+
+            let epoll_mgr = EpollManager::default();
+            let mut event_mgr = EventManager::new(&vmm, epoll_mgr).unwrap();
+            let mut v = vmm.lock().unwrap();
+
+            let vm = v.get_vm_mut().unwrap();
+            vm.set_instance_state(self.vm_state);
+
+            to_vmm.send(Box::new(self.req.take().unwrap())).unwrap();
+            assert!(vservice.run_vmm_action(&mut v, &mut event_mgr).is_ok());
+
+            let response = from_vmm.try_recv();
+            assert!(response.is_ok());
+            (self.f)(*response.unwrap());
+        }
+    }
+
+    #[test]
+    fn test_vmm_action_receive_unknown() {
+        skip_if_not_root!();
+
+        let (_to_vmm, from_api) = channel();
+        let (to_api, _from_vmm) = channel();
+        let vmm = Arc::new(Mutex::new(create_vmm_instance()));
+        let mut vservice = VmmService::new(from_api, to_api);
+        let epoll_mgr = EpollManager::default();
+        let mut event_mgr = EventManager::new(&vmm, epoll_mgr).unwrap();
+        let mut v = vmm.lock().unwrap();
 
         assert!(vservice.run_vmm_action(&mut v, &mut event_mgr).is_ok());
     }
@@ -976,6 +1750,65 @@ mod tests {
                     assert!(result.is_ok());
                 },
             ),
+            // pci transport requested without the `pci` feature enabled
+            #[cfg(not(feature = "pci"))]
+            TestData::new(
+                VmmAction::SetVmConfiguration(VmConfigInfo {
+                    device_transport: DeviceTransportType::Pci,
+                    ..Default::default()
+                }),
+                InstanceState::Uninitialized,
+                &|result| {
+                    assert!(matches!(
+                        result,
+                        Err(VmmActionError::MachineConfig(
+                            VmConfigError::InvalidDeviceTransport
+                        ))
+                    ));
+                },
+            ),
+            // numa memory doesn't sum to mem_size_mib
+            TestData::new(
+                VmmAction::SetVmConfiguration(VmConfigInfo {
+                    vcpu_count: 2,
+                    max_vcpu_count: 2,
+                    mem_size_mib: 1024,
+                    numa_nodes: NumaConfig {
+                        nodes: vec![NumaNode {
+                            node_id: 0,
+                            vcpu_ids: vec![0, 1],
+                            memory_mib: 512,
+                            device_ids: vec![],
+                        }],
+                        distances: vec![vec![10]],
+                    },
+                    ..Default::default()
+                }),
+                InstanceState::Uninitialized,
+                &|result| {
+                    assert!(matches!(
+                        result,
+                        Err(VmmActionError::MachineConfig(
+                            VmConfigError::NumaMemoryMismatch
+                        ))
+                    ));
+                },
+            ),
+            // max_phys_bits too small to fit RAM plus the device window
+            TestData::new(
+                VmmAction::SetVmConfiguration(VmConfigInfo {
+                    mem_size_mib: 1024,
+                    max_phys_bits: Some(1),
+                    ..Default::default()
+                }),
+                InstanceState::Uninitialized,
+                &|result| {
+                    assert!(matches!(
+                        result,
+                        Err(VmmActionError::MachineConfig(VmConfigError::InvalidPhysBits))
+                    ));
+                },
+            ),
         ];
 
         for t in tests.iter_mut() {
@@ -1221,6 +2054,22 @@ mod tests {
                     assert!(result.is_ok());
                 },
             ),
+            // vhost-user-fs backend requested without a socket path
+            TestData::new(
+                VmmAction::InsertFsDevice(FsDeviceConfigInfo {
+                    backend: FsBackendType::VhostUserFs {
+                        socket: String::new(),
+                    },
+                    ..Default::default()
+                }),
+                InstanceState::Uninitialized,
+                &|result| {
+                    assert!(matches!(
+                        result,
+                        Err(VmmActionError::BackendUnavailable(_))
+                    ));
+                },
+            ),
         ];
 
         for t in tests.iter_mut() {
@@ -1310,6 +2159,20 @@ mod tests {
                     assert!(result.is_ok());
                 },
             ),
+            // vhost-net kernel backend requested but unavailable on the test host
+            TestData::new(
+                VmmAction::InsertNetworkDevice(VirtioNetDeviceConfigInfo {
+                    backend: NetBackendType::VhostKernel,
+                    ..Default::default()
+                }),
+                InstanceState::Uninitialized,
+                &|result| {
+                    assert!(matches!(
+                        result,
+                        Err(VmmActionError::BackendUnavailable(_))
+                    ));
+                },
+            ),
         ];
 
         for t in tests.iter_mut() {
@@ -1372,7 +2235,7 @@ mod tests {
                     ));
                     let err_string = format!("{}", result.unwrap_err());
                     let expected_err = String::from(
-                        "failed to add virtio-vsock device: \
+                        "virtio-vsock device error: \
                     update operation is not allowed after boot",
                     );
                     assert_eq!(err_string, expected_err);
@@ -1389,7 +2252,7 @@ mod tests {
                     ));
                     let err_string = format!("{}", result.unwrap_err());
                     let expected_err = String::from(
-                        "failed to add virtio-vsock device: \
+                        "virtio-vsock device error: \
                     the guest CID 0 is invalid",
                     );
                     assert_eq!(err_string, expected_err);
@@ -1406,6 +2269,407 @@ mod tests {
                     assert!(result.is_ok());
                 },
             ),
+            // vhost-vsock kernel backend requested but unavailable on the test host
+            TestData::new(
+                VmmAction::InsertVsockDevice(VsockDeviceConfigInfo {
+                    guest_cid: 3,
+                    backend: VsockBackendType::VhostKernel,
+                    ..Default::default()
+                }),
+                InstanceState::Uninitialized,
+                &|result| {
+                    assert!(matches!(
+                        result,
+                        Err(VmmActionError::BackendUnavailable(_))
+                    ));
+                },
+            ),
+        ];
+
+        for t in tests.iter_mut() {
+            t.check_request();
+        }
+    }
+
+    #[cfg(feature = "virtio-vsock")]
+    #[test]
+    fn test_vmm_action_remove_vsock_device() {
+        skip_if_not_root!();
+
+        let tests = &mut [
+            // invalid state
+            TestData::new(
+                VmmAction::RemoveVsockDevice(String::from("vsock-1")),
+                InstanceState::Running,
+                &|result| {
+                    assert!(matches!(
+                        result,
+                        Err(VmmActionError::Vsock(
+                            VsockDeviceError::UpdateNotAllowedPostBoot
+                        ))
+                    ));
+                    let err_string = format!("{}", result.unwrap_err());
+                    let expected_err = String::from(
+                        "virtio-vsock device error: \
+                    update operation is not allowed after boot",
+                    );
+                    assert_eq!(err_string, expected_err);
+                },
+            ),
+            // invalid id
+            TestData::new(
+                VmmAction::RemoveVsockDevice(String::from("vsock-1")),
+                InstanceState::Uninitialized,
+                &|result| {
+                    assert!(matches!(
+                        result,
+                        Err(VmmActionError::Vsock(VsockDeviceError::InvalidDeviceId(_)))
+                    ));
+                },
+            ),
+        ];
+
+        for t in tests.iter_mut() {
+            t.check_request();
+        }
+    }
+
+    #[cfg(feature = "virtio-vsock")]
+    #[test]
+    fn test_vmm_action_update_vsock_rate_limiter() {
+        skip_if_not_root!();
+
+        let tests = &mut [
+            // invalid id
+            TestData::new(
+                VmmAction::UpdateVsockRateLimiter(VsockDeviceConfigUpdateInfo {
+                    id: String::from("vsock-1"),
+                    rx_rate_limiter: None,
+                    tx_rate_limiter: None,
+                }),
+                InstanceState::Running,
+                &|result| {
+                    assert!(matches!(
+                        result,
+                        Err(VmmActionError::Vsock(VsockDeviceError::InvalidDeviceId(_)))
+                    ));
+                },
+            ),
+        ];
+
+        for t in tests.iter_mut() {
+            t.check_request();
+        }
+    }
+
+    #[cfg(feature = "vfio")]
+    #[test]
+    fn test_vmm_action_insert_vfio_device() {
+        skip_if_not_root!();
+
+        let tests = &mut [
+            // invalid state
+            TestData::new(
+                VmmAction::InsertVfioDevice(VfioDeviceConfigInfo::default()),
+                InstanceState::Running,
+                &|result| {
+                    assert!(matches!(
+                        result,
+                        Err(VmmActionError::Vfio(VfioDeviceError::UpdateNotAllowedPostBoot))
+                    ));
+                    let err_string = format!("{}", result.unwrap_err());
+                    let expected_err = String::from(
+                        "vfio device error: \
+                    vfio device does not support runtime update",
+                    );
+                    assert_eq!(err_string, expected_err);
+                },
+            ),
+        ];
+
+        for t in tests.iter_mut() {
+            t.check_request();
+        }
+    }
+
+    #[cfg(feature = "vfio")]
+    #[test]
+    fn test_vmm_action_remove_vfio_device() {
+        skip_if_not_root!();
+
+        let tests = &mut [
+            // invalid state
+            TestData::new(
+                VmmAction::RemoveVfioDevice(String::from("0000:00:01.0")),
+                InstanceState::Running,
+                &|result| {
+                    assert!(matches!(
+                        result,
+                        Err(VmmActionError::Vfio(VfioDeviceError::UpdateNotAllowedPostBoot))
+                    ));
+                    let err_string = format!("{}", result.unwrap_err());
+                    let expected_err = String::from(
+                        "vfio device error: \
+                    vfio device does not support runtime update",
+                    );
+                    assert_eq!(err_string, expected_err);
+                },
+            ),
+        ];
+
+        for t in tests.iter_mut() {
+            t.check_request();
+        }
+    }
+
+    #[cfg(feature = "snapshot")]
+    #[test]
+    fn test_vmm_action_pause_microvm() {
+        skip_if_not_root!();
+
+        let tests = &mut [
+            // invalid state
+            TestData::new(
+                VmmAction::PauseMicroVm,
+                InstanceState::Uninitialized,
+                &|result| {
+                    assert!(matches!(
+                        result,
+                        Err(VmmActionError::Snapshot(SnapshotError::MicroVMNotRunning))
+                    ));
+                },
+            ),
+        ];
+
+        for t in tests.iter_mut() {
+            t.check_request();
+        }
+    }
+
+    #[cfg(feature = "snapshot")]
+    #[test]
+    fn test_vmm_action_resume_microvm() {
+        skip_if_not_root!();
+
+        let tests = &mut [
+            // invalid state
+            TestData::new(
+                VmmAction::ResumeMicroVm,
+                InstanceState::Uninitialized,
+                &|result| {
+                    assert!(matches!(
+                        result,
+                        Err(VmmActionError::Snapshot(SnapshotError::MicroVMNotRunning))
+                    ));
+                },
+            ),
+        ];
+
+        for t in tests.iter_mut() {
+            t.check_request();
+        }
+    }
+
+    #[cfg(feature = "snapshot")]
+    #[test]
+    fn test_vmm_action_snapshot_microvm() {
+        skip_if_not_root!();
+
+        let tests = &mut [
+            // invalid state
+            TestData::new(
+                VmmAction::SnapshotMicroVm(SnapshotConfig::default()),
+                InstanceState::Uninitialized,
+                &|result| {
+                    assert!(matches!(
+                        result,
+                        Err(VmmActionError::Snapshot(SnapshotError::MicroVMNotRunning))
+                    ));
+                    let err_string = format!("{}", result.unwrap_err());
+                    let expected_err = String::from(
+                        "failed to snapshot/restore the VM: \
+                    the virtual machine is not running",
+                    );
+                    assert_eq!(err_string, expected_err);
+                },
+            ),
+        ];
+
+        for t in tests.iter_mut() {
+            t.check_request();
+        }
+    }
+
+    #[cfg(feature = "snapshot")]
+    #[test]
+    fn test_vmm_action_restore_microvm() {
+        skip_if_not_root!();
+
+        let tests = &mut [
+            // invalid state
+            TestData::new(
+                VmmAction::RestoreMicroVm(RestoreConfig::default()),
+                InstanceState::Running,
+                &|result| {
+                    assert!(matches!(
+                        result,
+                        Err(VmmActionError::Snapshot(SnapshotError::StateInvalid))
+                    ));
+                },
+            ),
+        ];
+
+        for t in tests.iter_mut() {
+            t.check_request();
+        }
+    }
+
+    #[cfg(feature = "hotplug")]
+    #[test]
+    fn test_vmm_action_update_vm_configuration() {
+        skip_if_not_root!();
+
+        let tests = &mut [
+            // not running yet
+            TestData::new(
+                VmmAction::UpdateVmConfiguration(VmConfigUpdateInfo {
+                    vcpu_count: Some(4),
+                    mem_size_mib: None,
+                }),
+                InstanceState::Uninitialized,
+                &|result| {
+                    assert!(matches!(
+                        result,
+                        Err(VmmActionError::MachineConfig(
+                            VmConfigError::MicroVMNotRunning
+                        ))
+                    ));
+                },
+            ),
+        ];
+
+        for t in tests.iter_mut() {
+            t.check_request();
+        }
+    }
+
+    #[test]
+    fn test_vmm_action_set_console_config() {
+        skip_if_not_root!();
+
+        let tests = &mut [
+            // invalid state
+            TestData::new(
+                VmmAction::SetConsoleConfig(ConsoleConfigInfo {
+                    mode: ConsoleOutputMode::Off,
+                }),
+                InstanceState::Running,
+                &|result| {
+                    assert!(matches!(
+                        result,
+                        Err(VmmActionError::ConsoleConfig(
+                            ConsoleConfigError::UpdateNotAllowedPostBoot
+                        ))
+                    ));
+                },
+            ),
+        ];
+
+        for t in tests.iter_mut() {
+            t.check_request();
+        }
+    }
+
+    #[cfg(feature = "gdb")]
+    #[test]
+    fn test_vmm_action_gdb_server() {
+        skip_if_not_root!();
+
+        let tests = &mut [
+            // not running yet
+            TestData::new(
+                VmmAction::StartGdbServer {
+                    socket_path: String::from("/tmp/dragonball-gdb.sock"),
+                },
+                InstanceState::Uninitialized,
+                &|result| {
+                    assert!(matches!(
+                        result,
+                        Err(VmmActionError::GdbServer(GdbServerError::MicroVMNotRunning))
+                    ));
+                },
+            ),
+            // no server attached
+            TestData::new(
+                VmmAction::StopGdbServer,
+                InstanceState::Running,
+                &|result| {
+                    assert!(matches!(
+                        result,
+                        Err(VmmActionError::GdbServer(GdbServerError::NotRunning))
+                    ));
+                },
+            ),
+        ];
+
+        for t in tests.iter_mut() {
+            t.check_request();
+        }
+    }
+
+    #[cfg(feature = "coredump")]
+    #[test]
+    fn test_vmm_action_create_coredump() {
+        skip_if_not_root!();
+
+        let tests = &mut [
+            // invalid state
+            TestData::new(
+                VmmAction::CreateCoredump {
+                    destination: String::from("/tmp/dragonball.core"),
+                },
+                InstanceState::Uninitialized,
+                &|result| {
+                    assert!(matches!(
+                        result,
+                        Err(VmmActionError::Coredump(CoredumpError::InvalidVmState))
+                    ));
+                },
+            ),
+        ];
+
+        for t in tests.iter_mut() {
+            t.check_request();
+        }
+    }
+
+    #[cfg(feature = "virtio-net")]
+    #[test]
+    fn test_vmm_action_insert_vhost_user_net_device() {
+        skip_if_not_root!();
+
+        let tests = &mut [
+            // hotplug unready
+            TestData::new(
+                VmmAction::InsertVhostUserNetDevice(VhostUserNetDeviceConfigInfo::default()),
+                InstanceState::Running,
+                &|result| {
+                    assert!(matches!(
+                        result,
+                        Err(VmmActionError::StartMicroVm(
+                            StartMicroVmError::UpcallMissVsock
+                        ))
+                    ));
+                },
+            ),
+            // success
+            TestData::new(
+                VmmAction::InsertVhostUserNetDevice(VhostUserNetDeviceConfigInfo::default()),
+                InstanceState::Uninitialized,
+                &|result| {
+                    assert!(result.is_ok());
+                },
+            ),
         ];
 
         for t in tests.iter_mut() {
@@ -0,0 +1,26 @@
+// Copyright (C) 2020-2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#[cfg(feature = "virtio-fs")]
+/// Selects which process implements the virtio-fs filesystem daemon.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FsBackendType {
+    /// The filesystem daemon (e.g. virtiofsd's vhost-user-less mode) runs inside this process,
+    /// sharing the device manager's epoll loop directly (current behavior).
+    VirtioFsInner,
+    /// The filesystem daemon runs out-of-process and is reached over a vhost-user Unix-domain
+    /// socket. This device layer performs the vhost-user handshake (`GET_FEATURES`/
+    /// `SET_FEATURES`, `SET_MEM_TABLE`, and per-queue `SET_VRING_NUM`/`_ADDR`/`_BASE`/`_KICK`/
+    /// `_CALL`); handshake or connection failures surface through
+    /// [`FsDeviceError::AttachBackendFailed`].
+    VhostUserFs {
+        /// Path of the vhost-user control socket exposed by the daemon process.
+        socket: String,
+    },
+}
+
+impl Default for FsBackendType {
+    fn default() -> Self {
+        FsBackendType::VirtioFsInner
+    }
+}
@@ -0,0 +1,23 @@
+// Copyright (C) 2020-2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#[cfg(feature = "virtio-net")]
+/// Selects where a virtio-net device's datapath is processed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NetBackendType {
+    /// Packets are moved between the tap device and the guest entirely within this process
+    /// (current behavior).
+    InnerVmm,
+    /// Packets are offloaded to the host kernel's `/dev/vhost-net`: this device layer issues
+    /// `VHOST_SET_OWNER`/`VHOST_SET_FEATURES`, programs the vrings, and binds the backend to the
+    /// already-open tap fd via `VHOST_NET_SET_BACKEND`. Because the kernel moves packets
+    /// directly between the tap device and the guest, the in-VMM token-bucket rate limiters on
+    /// `VirtioNetDeviceConfigInfo` are not consulted for this backend.
+    VhostKernel,
+}
+
+impl Default for NetBackendType {
+    fn default() -> Self {
+        NetBackendType::InnerVmm
+    }
+}
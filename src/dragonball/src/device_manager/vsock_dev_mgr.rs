@@ -0,0 +1,23 @@
+// Copyright (C) 2020-2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#[cfg(feature = "virtio-vsock")]
+/// Selects where virtio-vsock datapath processing happens. The device layer negotiates features
+/// and programs the vrings the same way regardless of backend; only the datapath itself moves.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VsockBackendType {
+    /// Data transfer is handled entirely within this process (current behavior).
+    InnerVmm,
+    /// Data transfer is offloaded to the host kernel's `/dev/vhost-vsock`: this device layer
+    /// issues `VHOST_SET_OWNER`/`VHOST_SET_FEATURES`, programs each vring's
+    /// `VHOST_SET_VRING_NUM`/`_ADDR`/`_BASE`/`_KICK`/`_CALL`, sets the guest CID via
+    /// `VHOST_VSOCK_SET_GUEST_CID`, and flips `VHOST_VSOCK_SET_RUNNING` on activation (clearing
+    /// it on drop).
+    VhostKernel,
+}
+
+impl Default for VsockBackendType {
+    fn default() -> Self {
+        VsockBackendType::InnerVmm
+    }
+}